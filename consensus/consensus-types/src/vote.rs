@@ -5,8 +5,8 @@ use crate::{
     common::Author, quorum_cert::QuorumCert, timeout::Timeout, timeout_2chain::TwoChainTimeout,
     vote_data::VoteData,
 };
-use anyhow::{ensure, Context};
-use aptos_crypto::{ed25519::Ed25519Signature, hash::CryptoHash};
+use anyhow::{bail, ensure, Context};
+use aptos_crypto::{bls12381, ed25519::Ed25519Signature, hash::CryptoHash};
 use aptos_types::{
     ledger_info::LedgerInfo, validator_signer::ValidatorSigner,
     validator_verifier::ValidatorVerifier,
@@ -15,6 +15,130 @@ use serde::{Deserialize, Serialize};
 use short_hex_str::AsShortHexStr;
 use std::fmt::{Debug, Display, Formatter};
 
+/// A vote signature, generic over the underlying signature scheme (`Ed25519` or `Bls`).
+///
+/// This only lets a single `Vote` carry either scheme and verifies it against its one author's
+/// key; the QC/TC assembly, signer bitmap, and aggregation that would collapse a BLS-signed
+/// quorum into one signature don't exist yet and live wherever votes get collected.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub enum VoteSignature {
+    Ed25519(Ed25519Signature),
+    Bls(bls12381::Signature),
+}
+
+impl VoteSignature {
+    pub fn is_bls(&self) -> bool {
+        matches!(self, VoteSignature::Bls(_))
+    }
+}
+
+/// A single `(author, message, signature)` entry queued for batch verification.
+struct SignatureBatchEntry {
+    author: Author,
+    message: Vec<u8>,
+    signature: Ed25519Signature,
+}
+
+/// Accumulates Ed25519 `(author, message, signature)` triples from one or more votes for a single
+/// batched verification, instead of one `ValidatorVerifier::verify` call per signature. `Bls`
+/// vote signatures aren't covered by this batch; queuing one is an error.
+#[derive(Default)]
+pub struct SignatureBatch {
+    entries: Vec<SignatureBatchEntry>,
+}
+
+impl SignatureBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push<T: CryptoHash + Serialize>(
+        &mut self,
+        author: Author,
+        message: &T,
+        signature: &VoteSignature,
+    ) -> anyhow::Result<()> {
+        match signature {
+            VoteSignature::Ed25519(signature) => {
+                self.entries.push(SignatureBatchEntry {
+                    author,
+                    message: aptos_crypto::signing_message(message)?,
+                    signature: signature.clone(),
+                });
+                Ok(())
+            },
+            VoteSignature::Bls(_) => {
+                bail!("BLS vote signatures are aggregated, not batch-verified")
+            },
+        }
+    }
+
+    /// Number of entries currently queued. Used by `Vote::add_to_batch` to roll back a vote's
+    /// entries if queuing any one of them fails partway through.
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.entries.truncate(len);
+    }
+
+    /// Verifies every queued entry in a single batched operation. Returns `Ok(())` if all entries
+    /// are valid, or the indices (in push order) of the entries that failed.
+    pub fn verify(&self, validator: &ValidatorVerifier) -> Result<(), Vec<usize>> {
+        validator.verify_batch(
+            self.entries
+                .iter()
+                .map(|entry| (entry.author, entry.message.as_slice(), &entry.signature)),
+        )
+    }
+}
+
+/// A backend capable of producing the signatures a `Vote` needs, without requiring the signing
+/// key to live in the consensus process (e.g. an HSM or networked signing daemon reached over
+/// RPC, à la EIP-3030 remote signers). Every method is given the value whose canonical bytes are
+/// signed (for `sign_2chain_timeout`, those of `timeout.signing_format()`) together with the
+/// vote's epoch and round: `Timeout` and `TwoChainTimeout` carry them, and `sign_ledger_info` also
+/// receives the `VoteData` that the `LedgerInfo`'s consensus data hash commits to. A remote
+/// implementation can thus enforce monotonic epoch/round rules and refuse to sign a conflicting
+/// vote for a round it already signed. Generic over `VoteSignature` so Ed25519- and BLS-keyed
+/// validators can both use a remote signer; `ValidatorSigner` is the in-process, Ed25519-only
+/// implementation.
+#[async_trait::async_trait]
+pub trait VoteSigningBackend: Send + Sync {
+    async fn sign_ledger_info(
+        &self,
+        vote_data: &VoteData,
+        ledger_info: &LedgerInfo,
+    ) -> anyhow::Result<VoteSignature>;
+
+    async fn sign_timeout(&self, timeout: &Timeout) -> anyhow::Result<VoteSignature>;
+
+    async fn sign_2chain_timeout(&self, timeout: &TwoChainTimeout) -> anyhow::Result<VoteSignature>;
+}
+
+#[async_trait::async_trait]
+impl VoteSigningBackend for ValidatorSigner {
+    async fn sign_ledger_info(
+        &self,
+        _vote_data: &VoteData,
+        ledger_info: &LedgerInfo,
+    ) -> anyhow::Result<VoteSignature> {
+        Ok(VoteSignature::Ed25519(self.sign(ledger_info)))
+    }
+
+    async fn sign_timeout(&self, timeout: &Timeout) -> anyhow::Result<VoteSignature> {
+        Ok(VoteSignature::Ed25519(self.sign(timeout)))
+    }
+
+    async fn sign_2chain_timeout(
+        &self,
+        timeout: &TwoChainTimeout,
+    ) -> anyhow::Result<VoteSignature> {
+        Ok(VoteSignature::Ed25519(self.sign(&timeout.signing_format())))
+    }
+}
+
 /// Vote is the struct that is ultimately sent by the voter in response for
 /// receiving a proposal.
 /// Vote carries the `LedgerInfo` of a block that is going to be committed in case this vote
@@ -28,12 +152,12 @@ pub struct Vote {
     /// LedgerInfo of a block that is going to be committed in case this vote gathers QC.
     ledger_info: LedgerInfo,
     /// Signature of the LedgerInfo
-    signature: Ed25519Signature,
+    signature: VoteSignature,
     /// The round signatures can be aggregated into a timeout certificate if present.
-    timeout_signature: Option<Ed25519Signature>,
+    timeout_signature: Option<VoteSignature>,
     /// The 2-chain timeout and corresponding signature.
     #[serde(default, skip_serializing_if = "Option::is_none")]
-    two_chain_timeout: Option<(TwoChainTimeout, Ed25519Signature)>,
+    two_chain_timeout: Option<(TwoChainTimeout, VoteSignature)>,
 }
 
 // this is required by structured log
@@ -67,7 +191,12 @@ impl Vote {
     ) -> Self {
         ledger_info_placeholder.set_consensus_data_hash(vote_data.hash());
         let signature = validator_signer.sign(&ledger_info_placeholder);
-        Self::new_with_signature(vote_data, author, ledger_info_placeholder, signature)
+        Self::new_with_signature(
+            vote_data,
+            author,
+            ledger_info_placeholder,
+            VoteSignature::Ed25519(signature),
+        )
     }
 
     /// Generates a new Vote using a signature over the specified ledger_info
@@ -75,7 +204,7 @@ impl Vote {
         vote_data: VoteData,
         author: Author,
         ledger_info: LedgerInfo,
-        signature: Ed25519Signature,
+        signature: VoteSignature,
     ) -> Self {
         Self {
             vote_data,
@@ -87,9 +216,29 @@ impl Vote {
         }
     }
 
+    /// Generates a new Vote the same way as `new`, but delegates the signing to a
+    /// `VoteSigningBackend` instead of signing in-process.
+    pub async fn new_async(
+        vote_data: VoteData,
+        author: Author,
+        mut ledger_info_placeholder: LedgerInfo,
+        backend: &dyn VoteSigningBackend,
+    ) -> anyhow::Result<Self> {
+        ledger_info_placeholder.set_consensus_data_hash(vote_data.hash());
+        let signature = backend
+            .sign_ledger_info(&vote_data, &ledger_info_placeholder)
+            .await?;
+        Ok(Self::new_with_signature(
+            vote_data,
+            author,
+            ledger_info_placeholder,
+            signature,
+        ))
+    }
+
     /// Generates a round signature, which can then be used for aggregating a timeout certificate.
     /// Typically called for generating vote messages that are sent upon timeouts.
-    pub fn add_timeout_signature(&mut self, signature: Ed25519Signature) {
+    pub fn add_timeout_signature(&mut self, signature: VoteSignature) {
         assert!(
             self.two_chain_timeout.is_none(),
             "2-chain timeout shouldn't co-exist with timeout"
@@ -97,19 +246,68 @@ impl Vote {
         if self.timeout_signature.is_some() {
             return; // round signature is already set
         }
+        assert_eq!(
+            signature.is_bls(),
+            self.signature.is_bls(),
+            "timeout signature must use the same signature scheme as the vote"
+        );
+        self.timeout_signature.replace(signature);
+    }
 
+    /// Same as `add_timeout_signature`, but delegates the signing to a `VoteSigningBackend`.
+    pub async fn add_timeout_signature_async(
+        &mut self,
+        backend: &dyn VoteSigningBackend,
+    ) -> anyhow::Result<()> {
+        assert!(
+            self.two_chain_timeout.is_none(),
+            "2-chain timeout shouldn't co-exist with timeout"
+        );
+        if self.timeout_signature.is_some() {
+            return Ok(()); // round signature is already set
+        }
+        let signature = backend.sign_timeout(&self.generate_timeout()).await?;
+        ensure!(
+            signature.is_bls() == self.signature.is_bls(),
+            "timeout signature must use the same signature scheme as the vote"
+        );
         self.timeout_signature.replace(signature);
+        Ok(())
     }
 
     /// Add the 2-chain timeout and signature in the vote.
-    pub fn add_2chain_timeout(&mut self, timeout: TwoChainTimeout, signature: Ed25519Signature) {
+    pub fn add_2chain_timeout(&mut self, timeout: TwoChainTimeout, signature: VoteSignature) {
         assert!(
             self.timeout_signature.is_none(),
             "2-chain timeout shouldn't co-exist with timeout"
         );
+        assert_eq!(
+            signature.is_bls(),
+            self.signature.is_bls(),
+            "2-chain timeout signature must use the same signature scheme as the vote"
+        );
         self.two_chain_timeout = Some((timeout, signature));
     }
 
+    /// Same as `add_2chain_timeout`, but delegates the signing to a `VoteSigningBackend`.
+    pub async fn add_2chain_timeout_async(
+        &mut self,
+        timeout: TwoChainTimeout,
+        backend: &dyn VoteSigningBackend,
+    ) -> anyhow::Result<()> {
+        assert!(
+            self.timeout_signature.is_none(),
+            "2-chain timeout shouldn't co-exist with timeout"
+        );
+        let signature = backend.sign_2chain_timeout(&timeout).await?;
+        ensure!(
+            signature.is_bls() == self.signature.is_bls(),
+            "2-chain timeout signature must use the same signature scheme as the vote"
+        );
+        self.two_chain_timeout = Some((timeout, signature));
+        Ok(())
+    }
+
     pub fn vote_data(&self) -> &VoteData {
         &self.vote_data
     }
@@ -125,7 +323,7 @@ impl Vote {
     }
 
     /// Return the signature of the vote
-    pub fn signature(&self) -> &Ed25519Signature {
+    pub fn signature(&self) -> &VoteSignature {
         &self.signature
     }
 
@@ -153,12 +351,12 @@ impl Vote {
 
     /// Returns the signature for the vote_data().proposed().round() that can be aggregated for
     /// TimeoutCertificate.
-    pub fn timeout_signature(&self) -> Option<&Ed25519Signature> {
+    pub fn timeout_signature(&self) -> Option<&VoteSignature> {
         self.timeout_signature.as_ref()
     }
 
     /// Return the two chain timeout vote and signature.
-    pub fn two_chain_timeout(&self) -> Option<&(TwoChainTimeout, Ed25519Signature)> {
+    pub fn two_chain_timeout(&self) -> Option<&(TwoChainTimeout, VoteSignature)> {
         self.two_chain_timeout.as_ref()
     }
 
@@ -179,13 +377,16 @@ impl Vote {
             self.timeout_signature.is_none() || self.two_chain_timeout.is_none(),
             "Only one timeout should exist"
         );
-        validator
-            .verify(self.author(), &self.ledger_info, &self.signature)
+        Self::verify_signature(validator, self.author(), &self.ledger_info, &self.signature)
             .context("Failed to verify Vote")?;
         if let Some(timeout_signature) = &self.timeout_signature {
-            validator
-                .verify(self.author(), &self.generate_timeout(), timeout_signature)
-                .context("Failed to verify Timeout Vote")?;
+            Self::verify_signature(
+                validator,
+                self.author(),
+                &self.generate_timeout(),
+                timeout_signature,
+            )
+            .context("Failed to verify Timeout Vote")?;
         }
         if let Some((timeout, signature)) = &self.two_chain_timeout {
             ensure!(
@@ -194,12 +395,258 @@ impl Vote {
                 "2-chain timeout has different (epoch, round) than Vote"
             );
             timeout.verify(validator)?;
-            validator
-                .verify(self.author(), &timeout.signing_format(), signature)
+            Self::verify_signature(validator, self.author(), &timeout.signing_format(), signature)
                 .context("Failed to verify 2-chain timeout signature")?;
         }
         // Let us verify the vote data as well
         self.vote_data().verify()?;
         Ok(())
     }
+
+    /// Queues this vote's (and, if present, its timeout's) signature checks onto `batch` for
+    /// later batched verification, along with the consensus-data-hash equality assertion that
+    /// `verify` would otherwise perform eagerly. This is the preferred way to check many votes
+    /// at once, e.g. during epoch-change or sync. Unlike `verify`, it does not verify the quorum
+    /// cert carried by a 2-chain timeout. Transactional: if any check here fails, none of
+    /// this vote's entries are left queued in `batch`.
+    pub fn add_to_batch(&self, batch: &mut SignatureBatch) -> anyhow::Result<()> {
+        let checkpoint = batch.len();
+        let result = self.try_add_to_batch(batch);
+        if result.is_err() {
+            batch.truncate(checkpoint);
+        }
+        result
+    }
+
+    fn try_add_to_batch(&self, batch: &mut SignatureBatch) -> anyhow::Result<()> {
+        ensure!(
+            self.ledger_info.consensus_data_hash() == self.vote_data.hash(),
+            "Vote's hash mismatch with LedgerInfo"
+        );
+        ensure!(
+            self.timeout_signature.is_none() || self.two_chain_timeout.is_none(),
+            "Only one timeout should exist"
+        );
+        batch.push(self.author(), &self.ledger_info, &self.signature)?;
+        if let Some(timeout_signature) = &self.timeout_signature {
+            batch.push(self.author(), &self.generate_timeout(), timeout_signature)?;
+        }
+        if let Some((timeout, signature)) = &self.two_chain_timeout {
+            ensure!(
+                (timeout.epoch(), timeout.round())
+                    == (self.epoch(), self.vote_data.proposed().round()),
+                "2-chain timeout has different (epoch, round) than Vote"
+            );
+            batch.push(self.author(), &timeout.signing_format(), signature)?;
+        }
+        self.vote_data().verify()?;
+        Ok(())
+    }
+
+    /// True if `self` and `other` are a genuine equivocation: same author, same (epoch, round),
+    /// but a different consensus data hash (i.e. vote data) in their `LedgerInfo`s. Votes whose
+    /// `LedgerInfo`s differ only in the commit info are not treated as conflicting.
+    pub fn conflicts_with(&self, other: &Vote) -> bool {
+        self.author == other.author
+            && self.epoch() == other.epoch()
+            && self.vote_data.proposed().round() == other.vote_data.proposed().round()
+            && self.ledger_info.consensus_data_hash() != other.ledger_info.consensus_data_hash()
+    }
+
+    /// Dispatches signature verification to the scheme the signature was produced with.
+    fn verify_signature<T: CryptoHash + Serialize>(
+        validator: &ValidatorVerifier,
+        author: Author,
+        message: &T,
+        signature: &VoteSignature,
+    ) -> anyhow::Result<()> {
+        match signature {
+            VoteSignature::Ed25519(signature) => validator
+                .verify(author, message, signature)
+                .map_err(Into::into),
+            // A single vote's BLS signature is checked against its one author's already-registered
+            // public key, which is safe regardless of proof-of-possession: PoP only matters once
+            // several voters' keys get aggregated into a combined key, which happens where votes
+            // are collected, not here.
+            VoteSignature::Bls(signature) => validator
+                .verify_bls(author, message, signature)
+                .map_err(Into::into),
+        }
+    }
+}
+
+/// Self-contained, serializable slashing evidence that a validator double-voted: two `Vote`s from
+/// the same author for the same (epoch, round) whose `LedgerInfo`s carry different consensus data
+/// hashes.
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct VoteEquivocationProof {
+    a: Vote,
+    b: Vote,
+}
+
+// this is required by structured log
+impl Debug for VoteEquivocationProof {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "VoteEquivocationProof: [a: {}, b: {}]", self.a, self.b)
+    }
+}
+
+impl VoteEquivocationProof {
+    pub fn new(a: Vote, b: Vote) -> Self {
+        Self { a, b }
+    }
+
+    pub fn author(&self) -> Author {
+        self.a.author()
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.a.epoch()
+    }
+
+    /// Verifies both votes are independently signature-valid and that they genuinely conflict.
+    pub fn verify(&self, validator: &ValidatorVerifier) -> anyhow::Result<()> {
+        self.a
+            .verify(validator)
+            .context("Failed to verify first vote in equivocation proof")?;
+        self.b
+            .verify(validator)
+            .context("Failed to verify second vote in equivocation proof")?;
+        ensure!(
+            self.a.conflicts_with(&self.b),
+            "The two votes in this equivocation proof do not actually conflict"
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aptos_types::{block_info::BlockInfo, validator_verifier::random_validator_verifier};
+
+    fn new_vote(round: u64, signer: &ValidatorSigner) -> Vote {
+        let vote_data = VoteData::new(BlockInfo::random(round), BlockInfo::random(round - 1));
+        let ledger_info_placeholder =
+            LedgerInfo::new(BlockInfo::random(round), aptos_crypto::HashValue::zero());
+        Vote::new(vote_data, signer.author(), ledger_info_placeholder, signer)
+    }
+
+    /// Like `new_vote`, but the proposed block gets the given id, so that two votes for the same
+    /// round can differ in their vote data (`BlockInfo::random` is deterministic).
+    fn new_vote_for_block(
+        round: u64,
+        id: aptos_crypto::HashValue,
+        signer: &ValidatorSigner,
+    ) -> Vote {
+        let base = BlockInfo::random(round);
+        let proposed = BlockInfo::new(
+            base.epoch(),
+            base.round(),
+            id,
+            base.executed_state_id(),
+            base.version(),
+            base.timestamp_usecs(),
+            None,
+        );
+        let vote_data = VoteData::new(proposed, BlockInfo::random(round - 1));
+        let ledger_info_placeholder =
+            LedgerInfo::new(BlockInfo::random(round), aptos_crypto::HashValue::zero());
+        Vote::new(vote_data, signer.author(), ledger_info_placeholder, signer)
+    }
+
+    #[test]
+    fn add_to_batch_rolls_back_on_partial_failure() {
+        let (signers, _) = random_validator_verifier(1, None, false);
+        let mut vote = new_vote(1, &signers[0]);
+        // Force a scheme mismatch between the vote's main and timeout signatures, bypassing the
+        // constructors' scheme-consistency checks, to exercise add_to_batch's failure path: the
+        // main signature pushes fine, then the (batch-unsupported) Bls timeout signature bails.
+        let bls_private_key = bls12381::PrivateKey::generate_for_testing();
+        let bls_signature = bls_private_key
+            .sign(&vote.generate_timeout())
+            .expect("signing cannot fail");
+        vote.timeout_signature = Some(VoteSignature::Bls(bls_signature));
+
+        let mut batch = SignatureBatch::new();
+        batch
+            .push(vote.author(), &vote.ledger_info, &vote.signature)
+            .unwrap();
+        let entries_before = batch.len();
+
+        assert!(vote.add_to_batch(&mut batch).is_err());
+        assert_eq!(batch.len(), entries_before);
+    }
+
+    #[test]
+    fn verify_accepts_a_correctly_signed_bls_vote() {
+        let author = aptos_types::account_address::AccountAddress::random();
+        let bls_private_key = bls12381::PrivateKey::generate_for_testing();
+        let bls_public_key = bls12381::PublicKey::from(&bls_private_key);
+        let verifier = ValidatorVerifier::new_single_bls(author, bls_public_key);
+
+        let vote_data = VoteData::new(BlockInfo::random(1), BlockInfo::random(0));
+        let mut ledger_info = LedgerInfo::new(BlockInfo::random(1), aptos_crypto::HashValue::zero());
+        ledger_info.set_consensus_data_hash(vote_data.hash());
+        let signature = bls_private_key.sign(&ledger_info).expect("signing cannot fail");
+        let vote = Vote::new_with_signature(
+            vote_data,
+            author,
+            ledger_info,
+            VoteSignature::Bls(signature),
+        );
+
+        vote.verify(&verifier).unwrap();
+    }
+
+    #[test]
+    fn conflicts_with_identical_vote_is_false() {
+        let (signers, _) = random_validator_verifier(1, None, false);
+        let vote = new_vote(1, &signers[0]);
+        assert!(!vote.conflicts_with(&vote));
+    }
+
+    #[test]
+    fn conflicts_with_different_author_is_false() {
+        let (signers, _) = random_validator_verifier(2, None, false);
+        let vote_a = new_vote(1, &signers[0]);
+        let vote_b = new_vote(1, &signers[1]);
+        assert!(!vote_a.conflicts_with(&vote_b));
+    }
+
+    #[test]
+    fn conflicts_with_different_round_is_false() {
+        let (signers, _) = random_validator_verifier(1, None, false);
+        let vote_a = new_vote(1, &signers[0]);
+        let vote_b = new_vote(2, &signers[0]);
+        assert!(!vote_a.conflicts_with(&vote_b));
+    }
+
+    #[test]
+    fn conflicts_with_same_author_round_different_ledger_info_is_true() {
+        let (signers, _) = random_validator_verifier(1, None, false);
+        let vote_a = new_vote(1, &signers[0]);
+        let vote_b =
+            new_vote_for_block(1, aptos_crypto::HashValue::sha3_256_of(b"other"), &signers[0]);
+        assert!(vote_a.conflicts_with(&vote_b));
+    }
+
+    #[test]
+    fn equivocation_proof_verifies_for_conflicting_votes() {
+        let (signers, verifier) = random_validator_verifier(1, None, false);
+        let vote_a = new_vote(1, &signers[0]);
+        let vote_b =
+            new_vote_for_block(1, aptos_crypto::HashValue::sha3_256_of(b"other"), &signers[0]);
+        let proof = VoteEquivocationProof::new(vote_a, vote_b);
+        proof.verify(&verifier).unwrap();
+    }
+
+    #[test]
+    fn equivocation_proof_rejects_non_conflicting_votes() {
+        let (signers, verifier) = random_validator_verifier(2, None, false);
+        let vote_a = new_vote(1, &signers[0]);
+        let vote_b = new_vote(1, &signers[1]);
+        let proof = VoteEquivocationProof::new(vote_a, vote_b);
+        assert!(proof.verify(&verifier).is_err());
+    }
 }